@@ -1,22 +1,44 @@
 use crate::{
     config::{log_schema, DataType, SourceConfig, SourceContext, SourceDescription},
-    event::{Event, LookupBuf, Value},
+    event::{BatchNotifier, BatchStatus, Event, LookupBuf, Value},
     internal_events::{KafkaEventFailed, KafkaEventReceived, KafkaOffsetUpdateFailed},
     kafka::KafkaAuthConfig,
     shutdown::ShutdownSignal,
     Pipeline,
 };
 use bytes::Bytes;
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use futures::{SinkExt, StreamExt};
 use rdkafka::{
+    client::ClientContext,
     config::ClientConfig,
-    consumer::{Consumer, StreamConsumer},
-    message::Message,
+    consumer::{
+        BaseConsumer, CommitMode as RdKafkaCommitMode, Consumer, ConsumerContext, Rebalance,
+        StreamConsumer,
+    },
+    message::{Header, Headers, Message},
+    producer::{FutureProducer, FutureRecord},
+    topic_partition_list::TopicPartitionList,
+    util::Timeout,
+    Offset,
 };
 use serde::{Deserialize, Serialize};
 use snafu::{ResultExt, Snafu};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// The topic, partition, and offset of a message whose event has a
+/// `BatchNotifier` attached, along with the receiver that resolves once the
+/// sink reports the event's delivery status, if acknowledgements are
+/// enabled. The Kafka offset is only stored once that receiver resolves to
+/// `BatchStatus::Delivered`.
+type FinalizeOffset = Option<(String, i32, i64, oneshot::Receiver<BatchStatus>)>;
 
 #[derive(Debug, Snafu)]
 enum BuildError {
@@ -24,6 +46,8 @@ enum BuildError {
     KafkaCreateError { source: rdkafka::error::KafkaError },
     #[snafu(display("Could not subscribe to Kafka topics: {}", source))]
     KafkaSubscribeError { source: rdkafka::error::KafkaError },
+    #[snafu(display("Could not create Kafka dead-letter queue producer: {}", source))]
+    KafkaCreateDlqProducerError { source: rdkafka::error::KafkaError },
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -50,11 +74,93 @@ pub struct KafkaSourceConfig {
     partition_key: LookupBuf,
     #[serde(default = "default_offset_key")]
     offset_key: LookupBuf,
+    #[serde(default = "default_headers_key")]
+    headers_key: LookupBuf,
     librdkafka_options: Option<HashMap<String, String>>,
+    dlq: Option<KafkaDlqConfig>,
+    #[serde(default)]
+    commit_mode: KafkaCommitMode,
+    /// When enabled, an offset is only stored once the sink has reported the
+    /// corresponding event as `BatchStatus::Delivered`, anchoring
+    /// at-least-once delivery to sink acknowledgement rather than to read
+    /// time. When disabled (the default), the offset is stored as soon as
+    /// the event is read, matching the source's historical behavior.
+    #[serde(default)]
+    acknowledgements: bool,
+    starting_offsets: Option<StartingOffsets>,
     #[serde(flatten)]
     auth: KafkaAuthConfig,
 }
 
+/// Where to start consuming from on initial partition assignment, applied in
+/// the post-assignment rebalance hook so it takes effect after the consumer
+/// group's partitions have been assigned.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum StartingOffsets {
+    /// Resolve to the earliest offset at or after this RFC3339 timestamp via
+    /// `offsets_for_times`. A partition for which no offset resolves (the
+    /// timestamp is later than its last message) is left where
+    /// `auto_offset_reset` puts it. A timestamp older than the earliest
+    /// retained message resolves to that message's offset; it does not fall
+    /// back to `auto_offset_reset`.
+    Timestamp(DateTime<Utc>),
+    /// Explicit offsets, keyed by `"topic:partition"`.
+    Explicit(HashMap<String, i64>),
+}
+
+/// Controls when consumed offsets are committed.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum KafkaCommitMode {
+    /// Rely on librdkafka's periodic auto-commit; offsets may be
+    /// re-delivered or skipped across a rebalance.
+    Auto,
+    /// Synchronously commit offsets for partitions about to be revoked
+    /// before they are handed to another consumer, and seek newly assigned
+    /// partitions back to their last committed position.
+    SyncOnRevoke,
+}
+
+impl Default for KafkaCommitMode {
+    fn default() -> Self {
+        KafkaCommitMode::Auto
+    }
+}
+
+/// Configuration for forwarding messages that could not be turned into a
+/// valid `Event` to a dead-letter Kafka topic, with a circuit breaker to
+/// stop consuming if too many messages are failing.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaDlqConfig {
+    /// The Kafka topic invalid/failed messages are forwarded to.
+    topic: String,
+    /// The number of most recent messages used to compute `max_invalid_ratio`.
+    #[serde(default = "default_dlq_window_size")]
+    window_size: usize,
+    /// If the ratio of failed messages over the last `window_size` messages
+    /// exceeds this value, the source stops consuming and surfaces an error.
+    #[serde(default = "default_max_invalid_ratio")]
+    max_invalid_ratio: f64,
+    /// If this many messages in a row fail, the source stops consuming and
+    /// surfaces an error, regardless of `max_invalid_ratio`.
+    #[serde(default = "default_max_consecutive_failures")]
+    max_consecutive_failures: usize,
+}
+
+fn default_dlq_window_size() -> usize {
+    100
+}
+
+fn default_max_invalid_ratio() -> f64 {
+    0.5
+}
+
+fn default_max_consecutive_failures() -> usize {
+    100
+}
+
 impl Default for KafkaSourceConfig {
     fn default() -> Self {
         Self {
@@ -70,7 +176,12 @@ impl Default for KafkaSourceConfig {
             topic_key: default_topic_key(),
             partition_key: default_partition_key(),
             offset_key: default_offset_key(),
+            headers_key: default_headers_key(),
             librdkafka_options: Default::default(),
+            dlq: Default::default(),
+            commit_mode: Default::default(),
+            acknowledgements: Default::default(),
+            starting_offsets: Default::default(),
             auth: Default::default(),
         }
     }
@@ -112,6 +223,10 @@ fn default_offset_key() -> LookupBuf {
     LookupBuf::from("offset")
 }
 
+fn default_headers_key() -> LookupBuf {
+    LookupBuf::from("headers")
+}
+
 inventory::submit! {
     SourceDescription::new::<KafkaSourceConfig>("kafka")
 }
@@ -134,6 +249,115 @@ impl SourceConfig for KafkaSourceConfig {
     }
 }
 
+/// Finalizes `acknowledgements`-gated offsets strictly in the order their
+/// messages were read, one background task per partition.
+///
+/// A message's delivery receiver resolves whenever its sink batch happens to
+/// complete, which isn't necessarily in read order (retries, batching, and
+/// concurrent sink requests can all let a later offset's ack land before an
+/// earlier one's). Storing offsets as soon as each individual receiver
+/// resolves would let a later offset silently skip past an earlier message
+/// that was never actually delivered. Each partition instead gets its own
+/// FIFO queue: messages are submitted to it in read order, and its task
+/// awaits (and stores) them one at a time, oldest first, so a later offset
+/// can never be stored ahead of an earlier one on the same partition.
+struct OffsetFinalizers {
+    consumer: Arc<StreamConsumer<KafkaSourceContext>>,
+    queues: Mutex<HashMap<(String, i32), OffsetQueueSender>>,
+    tasks: Mutex<Vec<tokio::task::JoinHandle<()>>>,
+}
+
+type OffsetQueueSender = mpsc::UnboundedSender<(i64, oneshot::Receiver<BatchStatus>)>;
+
+impl OffsetFinalizers {
+    fn new(consumer: Arc<StreamConsumer<KafkaSourceContext>>) -> Self {
+        Self {
+            consumer,
+            queues: Mutex::new(HashMap::new()),
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Queues a message's delivery receiver for in-order processing on its
+    /// partition's finalizer task. Never blocks the caller: submitting is a
+    /// single unbounded-channel send, so a slow or out-of-order ack on one
+    /// partition can't stall Kafka consumption.
+    fn submit(
+        &self,
+        topic: String,
+        partition: i32,
+        offset: i64,
+        receiver: oneshot::Receiver<BatchStatus>,
+    ) {
+        let mut queues = self.queues.lock().expect("offset finalizer mutex poisoned");
+        let sender = queues.entry((topic.clone(), partition)).or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let consumer = Arc::clone(&self.consumer);
+            let handle = tokio::spawn(Self::run_queue(consumer, topic, partition, receiver));
+            self.tasks
+                .lock()
+                .expect("offset finalizer mutex poisoned")
+                .push(handle);
+            sender
+        });
+        // The receiving end only closes once `shutdown` drops it, so this
+        // can only fail during shutdown, at which point dropping the
+        // message is correct: nothing will store its offset either way.
+        let _ = sender.send((offset, receiver));
+    }
+
+    async fn run_queue(
+        consumer: Arc<StreamConsumer<KafkaSourceContext>>,
+        topic: String,
+        partition: i32,
+        mut queue: mpsc::UnboundedReceiver<(i64, oneshot::Receiver<BatchStatus>)>,
+    ) {
+        while let Some((offset, receiver)) = queue.recv().await {
+            match receiver.await {
+                Ok(BatchStatus::Delivered) => {
+                    if let Err(error) =
+                        consumer.store_offset_from_offset(&topic, partition, offset)
+                    {
+                        emit!(KafkaOffsetUpdateFailed { error });
+                    }
+                }
+                Ok(BatchStatus::Errored) | Ok(BatchStatus::Rejected) => {
+                    error!(
+                        message = "Sink did not deliver event; not storing Kafka offset.",
+                        %topic,
+                        partition,
+                        offset
+                    );
+                }
+                Err(_) => {
+                    error!(
+                        message = "Lost delivery acknowledgement; not storing Kafka offset.",
+                        %topic,
+                        partition,
+                        offset
+                    );
+                }
+            }
+        }
+    }
+
+    /// Closes every partition's queue and waits for its task to drain,
+    /// so a normal shutdown doesn't drop an offset that was, in fact,
+    /// delivered while its ack was still in flight.
+    async fn shutdown(&self) {
+        self.queues
+            .lock()
+            .expect("offset finalizer mutex poisoned")
+            .clear();
+        let tasks = std::mem::take(
+            &mut *self.tasks.lock().expect("offset finalizer mutex poisoned"),
+        );
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+}
+
 fn kafka_source(
     config: &KafkaSourceConfig,
     shutdown: ShutdownSignal,
@@ -143,10 +367,20 @@ fn kafka_source(
     let topic_key = config.topic_key.clone();
     let partition_key = config.partition_key.clone();
     let offset_key = config.offset_key.clone();
+    let headers_key = config.headers_key.clone();
+    let acknowledgements = config.acknowledgements;
     let consumer = Arc::new(create_consumer(config)?);
+    let dlq = config
+        .dlq
+        .as_ref()
+        .map(|dlq_config| DlqHandler::new(config, dlq_config))
+        .transpose()?
+        .map(Arc::new);
 
     Ok(Box::pin(async move {
         let shutdown = shutdown;
+        let tripped = Arc::new(AtomicBool::new(false));
+        let finalizers = Arc::new(OffsetFinalizers::new(Arc::clone(&consumer)));
 
         Arc::clone(&consumer)
             .stream()
@@ -156,7 +390,11 @@ fn kafka_source(
                 let topic_key = topic_key.clone();
                 let partition_key = partition_key.clone();
                 let offset_key = offset_key.clone();
+                let headers_key = headers_key.clone();
                 let consumer = Arc::clone(&consumer);
+                let dlq = dlq.clone();
+                let tripped = Arc::clone(&tripped);
+                let acknowledgements = acknowledgements;
 
                 async move {
                     match message {
@@ -170,9 +408,21 @@ fn kafka_source(
                             });
 
                             let payload = match msg.payload() {
-                                None => return Err(()), // skip messages with empty payload
+                                None => {
+                                    return handle_invalid_message(
+                                        &consumer,
+                                        dlq.as_deref(),
+                                        &tripped,
+                                        &msg,
+                                        "message had an empty payload",
+                                    )
+                                    .await;
+                                }
                                 Some(payload) => payload,
                             };
+                            if let Some(dlq) = &dlq {
+                                dlq.record_success();
+                            }
                             let mut event = Event::new_empty_log();
                             let log = event.as_mut_log();
 
@@ -207,37 +457,150 @@ fn kafka_source(
 
                             log.insert(offset_key, Value::from(msg.offset()));
 
-                            consumer.store_offset(&msg).map_err(|error| {
-                                emit!(KafkaOffsetUpdateFailed { error });
-                            })?;
+                            if let Some(headers) = msg.headers() {
+                                log.insert(headers_key, parse_message_headers(headers));
+                            }
+
+                            // When acknowledgements are enabled, attach a `BatchNotifier` to
+                            // the event and defer storing the offset until the sink reports
+                            // it as delivered, anchoring at-least-once delivery to sink
+                            // acknowledgement rather than read time. Otherwise, fall back to
+                            // the old read-time behavior.
+                            let finalize_offset = if acknowledgements {
+                                let (batch, receiver) = BatchNotifier::new_with_receiver();
+                                event.add_batch_notifier(batch);
+                                Some((
+                                    msg.topic().to_string(),
+                                    msg.partition(),
+                                    msg.offset(),
+                                    receiver,
+                                ))
+                            } else {
+                                consumer.store_offset(&msg).map_err(|error| {
+                                    emit!(KafkaOffsetUpdateFailed { error });
+                                })?;
+                                None
+                            };
 
-                            Ok(event)
+                            Ok((Some(event), finalize_offset))
                         }
                     }
                 }
             })
+            .take_while({
+                let tripped = Arc::clone(&tripped);
+                move |_| {
+                    let tripped = !tripped.load(Ordering::Relaxed);
+                    async move { tripped }
+                }
+            })
             // Try `forward` after removing old futures.
             // Error: implementation of `futures_core::stream::Stream` is not general enough
             // .forward(
             //     out.sink_compat()
             //         .sink_map_err(|error| error!(message = "Error sending to sink.", %error)),
             // )
-            .for_each(|item| {
+            .for_each(move |item| {
                 let mut out = out.clone();
+                let finalizers = Arc::clone(&finalizers);
                 async move {
-                    if let Ok(item) = item {
-                        if let Err(error) = out.send(item).await {
+                    let (event, finalize_offset) = match item {
+                        Ok(item) => item,
+                        Err(()) => return,
+                    };
+
+                    // Invalid messages that were successfully handed off to the
+                    // dead-letter queue have nothing to forward downstream; their
+                    // offset was already stored synchronously once the DLQ write
+                    // succeeded.
+                    let event = match event {
+                        Some(event) => event,
+                        None => return,
+                    };
+
+                    match out.send(event).await {
+                        Ok(()) => {
+                            if let Some((topic, partition, offset, receiver)) = finalize_offset {
+                                // Submitting (not awaiting the receiver here) keeps
+                                // consumption moving; the partition's finalizer task
+                                // awaits and stores it in order, behind any earlier
+                                // offset on the same partition.
+                                finalizers.submit(topic, partition, offset, receiver);
+                            }
+                        }
+                        Err(error) => {
                             error!(message = "Error sending to sink.", %error);
                         }
                     }
                 }
             })
             .await;
+
+        finalizers.shutdown().await;
+
+        if tripped.load(Ordering::Relaxed) {
+            error!(message = "Kafka dead-letter circuit breaker tripped; stopped consuming.");
+            return Err(());
+        }
         Ok(())
     }))
 }
 
-fn create_consumer(config: &KafkaSourceConfig) -> crate::Result<StreamConsumer> {
+/// Handles a message that could not be turned into a valid `Event`: records
+/// the failure with the dead-letter circuit breaker, forwards the raw
+/// message to the DLQ target (if configured), and trips `tripped` if the
+/// circuit breaker's thresholds have been exceeded.
+///
+/// The source offset is only stored once the dead-letter write has actually
+/// succeeded — if there's no DLQ configured, or the write fails, this
+/// message's offset is not stored. An offset stored for a later message on
+/// the same partition still moves the committed position past it.
+async fn handle_invalid_message<M: Message>(
+    consumer: &StreamConsumer<KafkaSourceContext>,
+    dlq: Option<&DlqHandler>,
+    tripped: &AtomicBool,
+    msg: &M,
+    reason: &str,
+) -> Result<(Option<Event>, FinalizeOffset), ()> {
+    let dlq = match dlq {
+        Some(dlq) => dlq,
+        None => return Err(()),
+    };
+
+    let delivered = dlq.send(msg, reason).await;
+    if dlq.record_failure() {
+        tripped.store(true, Ordering::Relaxed);
+    }
+
+    if !delivered {
+        return Err(());
+    }
+
+    consumer.store_offset(msg).map_err(|error| {
+        emit!(KafkaOffsetUpdateFailed { error });
+    })?;
+
+    Ok((None, None))
+}
+
+/// Turns the headers of a Kafka message into a nested map `Value`, keyed by
+/// header name. Headers without a value are represented as `Value::Null`.
+fn parse_message_headers<H: Headers>(headers: &H) -> Value {
+    let mut map = BTreeMap::new();
+    for i in 0..headers.count() {
+        if let Some(Header { key, value }) = headers.get(i) {
+            let value = value
+                .map(|value| Value::from(Bytes::from(value.to_owned())))
+                .unwrap_or(Value::Null);
+            map.insert(key.to_string(), value);
+        }
+    }
+    Value::Map(map)
+}
+
+fn create_consumer(
+    config: &KafkaSourceConfig,
+) -> crate::Result<StreamConsumer<KafkaSourceContext>> {
     let mut client_config = ClientConfig::new();
     client_config
         .set("group.id", &config.group_id)
@@ -263,18 +626,332 @@ fn create_consumer(config: &KafkaSourceConfig) -> crate::Result<StreamConsumer>
         }
     }
 
-    let consumer: StreamConsumer = client_config.create().context(KafkaCreateError)?;
+    let context = KafkaSourceContext::new(config.commit_mode, config.starting_offsets.clone());
+    let consumer: StreamConsumer<KafkaSourceContext> = client_config
+        .create_with_context(context)
+        .context(KafkaCreateError)?;
     let topics: Vec<&str> = config.topics.iter().map(|s| s.as_str()).collect();
     consumer.subscribe(&topics).context(KafkaSubscribeError)?;
 
     Ok(consumer)
 }
 
+/// Custom `ConsumerContext` used so the source can react to rebalances:
+/// when `commit_mode` is `sync_on_revoke`, offsets for partitions about to
+/// be revoked are committed synchronously before they change hands, and
+/// newly assigned partitions are seeked either to an explicitly configured
+/// starting point (`starting_offsets`) or back to their last committed
+/// position, closing the window where a rebalance re-delivers or skips
+/// events across consumer group members.
+struct KafkaSourceContext {
+    commit_mode: KafkaCommitMode,
+    starting_offsets: Option<StartingOffsets>,
+    seeked_starting_offsets: AtomicBool,
+}
+
+impl KafkaSourceContext {
+    fn new(commit_mode: KafkaCommitMode, starting_offsets: Option<StartingOffsets>) -> Self {
+        Self {
+            commit_mode,
+            starting_offsets,
+            seeked_starting_offsets: AtomicBool::new(false),
+        }
+    }
+
+    /// Seeks newly assigned partitions to the configured `starting_offsets`.
+    /// Falls back to `auto_offset_reset` (i.e. does nothing) for any
+    /// partition the configured starting point doesn't resolve to an offset
+    /// for, e.g. a partition missing from the explicit offsets, or a
+    /// timestamp later than the partition's last message.
+    fn seek_to_starting_offsets(
+        &self,
+        consumer: &BaseConsumer<Self>,
+        partitions: &TopicPartitionList,
+    ) {
+        match self.starting_offsets.as_ref() {
+            None => {}
+            Some(StartingOffsets::Explicit(offsets)) => {
+                for element in partitions.elements() {
+                    let key = format!("{}:{}", element.topic(), element.partition());
+                    if let Some(offset) = offsets.get(&key) {
+                        if let Err(error) = consumer.seek(
+                            element.topic(),
+                            element.partition(),
+                            Offset::Offset(*offset),
+                            Timeout::Never,
+                        ) {
+                            error!(
+                                message = "Failed to seek to configured starting offset.",
+                                %error
+                            );
+                        }
+                    }
+                }
+            }
+            Some(StartingOffsets::Timestamp(timestamp)) => {
+                let mut search = TopicPartitionList::new();
+                for element in partitions.elements() {
+                    let _ = search.add_partition_offset(
+                        element.topic(),
+                        element.partition(),
+                        Offset::Offset(timestamp.timestamp_millis()),
+                    );
+                }
+                match consumer.offsets_for_times(search, Timeout::Never) {
+                    Ok(resolved) => {
+                        for element in resolved.elements() {
+                            if let Offset::Offset(offset) = element.offset() {
+                                if let Err(error) = consumer.seek(
+                                    element.topic(),
+                                    element.partition(),
+                                    Offset::Offset(offset),
+                                    Timeout::Never,
+                                ) {
+                                    error!(
+                                        message = "Failed to seek to resolved starting offset.",
+                                        %error
+                                    );
+                                }
+                            }
+                            // No offset resolved for this partition (the
+                            // timestamp is later than its last message): leave
+                            // it where `auto_offset_reset` put it.
+                        }
+                    }
+                    Err(error) => {
+                        error!(
+                            message = "Failed to resolve starting offsets from timestamp.",
+                            %error
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ClientContext for KafkaSourceContext {}
+
+impl ConsumerContext for KafkaSourceContext {
+    fn pre_rebalance(&self, consumer: &BaseConsumer<Self>, rebalance: &Rebalance) {
+        if self.commit_mode != KafkaCommitMode::SyncOnRevoke {
+            return;
+        }
+        if let Rebalance::Revoke(_) = rebalance {
+            // `Rebalance::Revoke`'s `TopicPartitionList` carries only the
+            // bare topic/partition pairs being revoked, with no offsets set;
+            // `commit_consumer_state` is the idiom for flushing every offset
+            // this consumer has locally stored before the partitions change
+            // hands.
+            if let Err(error) = consumer.commit_consumer_state(RdKafkaCommitMode::Sync) {
+                error!(message = "Failed to commit offsets for revoked partitions.", %error);
+            }
+        }
+    }
+
+    fn post_rebalance(&self, consumer: &BaseConsumer<Self>, rebalance: &Rebalance) {
+        let partitions = match rebalance {
+            Rebalance::Assign(partitions) => partitions,
+            _ => return,
+        };
+
+        // `starting_offsets` is only meant to apply to the initial
+        // assignment; subsequent rebalances (another consumer joining or
+        // leaving the group) must not snap this consumer back to the
+        // configured timestamp/offset and reprocess everything since.
+        if self.starting_offsets.is_some()
+            && self
+                .seeked_starting_offsets
+                .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.seek_to_starting_offsets(consumer, partitions);
+            return;
+        }
+
+        if self.commit_mode != KafkaCommitMode::SyncOnRevoke {
+            return;
+        }
+        match consumer.committed_offsets(partitions.clone(), Timeout::Never) {
+            Ok(committed) => {
+                for element in committed.elements() {
+                    if let Offset::Offset(offset) = element.offset() {
+                        if let Err(error) = consumer.seek(
+                            element.topic(),
+                            element.partition(),
+                            Offset::Offset(offset),
+                            Timeout::Never,
+                        ) {
+                            error!(
+                                message =
+                                    "Failed to seek to last committed offset after rebalance.",
+                                %error
+                            );
+                        }
+                    }
+                }
+            }
+            Err(error) => {
+                error!(message = "Failed to fetch committed offsets after rebalance.", %error);
+            }
+        }
+    }
+}
+
+/// A record written to the dead-letter topic when a message could not be
+/// turned into a valid `Event`.
+#[derive(Debug, Serialize)]
+struct DlqRecord {
+    topic: String,
+    partition: i32,
+    offset: i64,
+    key: Option<String>,
+    payload: Option<Vec<u8>>,
+    reason: String,
+}
+
+/// Forwards messages that could not be turned into a valid `Event` to a
+/// dead-letter Kafka topic, and trips a circuit breaker if too many messages
+/// are failing so that a poison-pill topic doesn't burn CPU in a fast loop.
+struct DlqHandler {
+    producer: FutureProducer,
+    topic: String,
+    window_size: usize,
+    max_invalid_ratio: f64,
+    max_consecutive_failures: usize,
+    window: Mutex<VecDeque<bool>>,
+    consecutive_failures: AtomicUsize,
+}
+
+impl DlqHandler {
+    fn new(config: &KafkaSourceConfig, dlq_config: &KafkaDlqConfig) -> crate::Result<Self> {
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &config.bootstrap_servers)
+            .set("client.id", "vector-dlq");
+        config.auth.apply(&mut client_config)?;
+
+        let producer: FutureProducer = client_config
+            .create()
+            .context(KafkaCreateDlqProducerError)?;
+
+        Ok(Self {
+            producer,
+            topic: dlq_config.topic.clone(),
+            window_size: dlq_config.window_size,
+            max_invalid_ratio: dlq_config.max_invalid_ratio,
+            max_consecutive_failures: dlq_config.max_consecutive_failures,
+            window: Mutex::new(VecDeque::with_capacity(dlq_config.window_size)),
+            consecutive_failures: AtomicUsize::new(0),
+        })
+    }
+
+    /// Forwards the raw message, along with the reason it could not be
+    /// turned into a valid `Event`, to the dead-letter topic. Returns
+    /// `true` only once the write has actually succeeded, so the caller can
+    /// decide whether it's safe to store the source offset.
+    async fn send<M: Message>(&self, msg: &M, reason: &str) -> bool {
+        let record = DlqRecord {
+            topic: msg.topic().to_string(),
+            partition: msg.partition(),
+            offset: msg.offset(),
+            key: msg
+                .key()
+                .map(|key| String::from_utf8_lossy(key).to_string()),
+            payload: msg.payload().map(|payload| payload.to_owned()),
+            reason: reason.to_string(),
+        };
+
+        let payload = match serde_json::to_vec(&record) {
+            Ok(payload) => payload,
+            Err(error) => {
+                error!(message = "Could not serialize dead-letter record.", %error);
+                return false;
+            }
+        };
+
+        let mut dlq_record = FutureRecord::to(&self.topic).payload(&payload);
+        if let Some(key) = &record.key {
+            dlq_record = dlq_record.key(key);
+        }
+
+        if let Err((error, _)) = self.producer.send(dlq_record, Timeout::Never).await {
+            error!(message = "Could not write to Kafka dead-letter queue.", %error);
+            return false;
+        }
+        true
+    }
+
+    /// Records that a message was successfully turned into an `Event`,
+    /// resetting the consecutive-failure count.
+    fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        self.push_window(false);
+    }
+
+    /// Records that a message could not be turned into an `Event`, whether
+    /// or not its dead-letter write succeeded. Returns `true` if the circuit
+    /// breaker has tripped and consumption should stop.
+    fn record_failure(&self) -> bool {
+        let consecutive = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        // Always fold the failure into the sliding window first, even if the
+        // consecutive-failure count alone is enough to trip the breaker, so
+        // `max_invalid_ratio` is tracked against a consistent history
+        // regardless of which threshold ends up tripping it.
+        let ratio_tripped = self.push_window(true);
+        consecutive >= self.max_consecutive_failures || ratio_tripped
+    }
+
+    fn push_window(&self, failed: bool) -> bool {
+        let mut window = self.window.lock().expect("DLQ window mutex poisoned");
+        window.push_back(failed);
+        if window.len() > self.window_size {
+            window.pop_front();
+        }
+        if window.len() < self.window_size {
+            return false;
+        }
+        let invalid = window.iter().filter(|failed| **failed).count();
+        (invalid as f64 / window.len() as f64) > self.max_invalid_ratio
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{kafka_source, KafkaSourceConfig};
+    use super::{kafka_source, DlqHandler, KafkaDlqConfig, KafkaSourceConfig};
     use crate::{event::LookupBuf, shutdown::ShutdownSignal, Pipeline};
 
+    fn make_dlq_config(window_size: usize, max_invalid_ratio: f64) -> KafkaDlqConfig {
+        KafkaDlqConfig {
+            topic: "my-topic-dlq".to_string(),
+            window_size,
+            max_invalid_ratio,
+            max_consecutive_failures: 1000,
+        }
+    }
+
+    #[test]
+    fn dlq_circuit_breaker_trips_on_consecutive_failures() {
+        let dlq_config = KafkaDlqConfig {
+            max_consecutive_failures: 3,
+            ..make_dlq_config(100, 1.0)
+        };
+        let handler = DlqHandler::new(&make_config(), &dlq_config).unwrap();
+        assert!(!handler.record_failure());
+        assert!(!handler.record_failure());
+        assert!(handler.record_failure());
+    }
+
+    #[test]
+    fn dlq_circuit_breaker_trips_on_invalid_ratio() {
+        let handler = DlqHandler::new(&make_config(), &make_dlq_config(4, 0.5)).unwrap();
+        handler.record_success();
+        handler.record_success();
+        assert!(!handler.record_failure());
+        assert!(!handler.record_failure());
+        assert!(handler.record_failure());
+    }
+
     #[test]
     fn generate_config() {
         crate::test_util::test_generate_config::<KafkaSourceConfig>();
@@ -327,6 +1004,7 @@ mod integration_test {
     use chrono::{SubsecRound, Utc};
     use rdkafka::{
         config::ClientConfig,
+        message::OwnedHeaders,
         producer::{FutureProducer, FutureRecord},
         util::Timeout,
     };
@@ -344,7 +1022,8 @@ mod integration_test {
         let record = FutureRecord::to(&topic)
             .payload(text)
             .key(key)
-            .timestamp(timestamp);
+            .timestamp(timestamp)
+            .headers(OwnedHeaders::new().add("foo", "bar"));
 
         if let Err(error) = producer.send(record, Timeout::Never).await {
             panic!("Cannot send event to Kafka: {:?}", error);
@@ -369,6 +1048,7 @@ mod integration_test {
             topic_key: LookupBuf::from("topic"),
             partition_key: LookupBuf::from("partition"),
             offset_key: LookupBuf::from("offset"),
+            headers_key: LookupBuf::from("headers"),
             socket_timeout_ms: 60000,
             fetch_wait_max_ms: 100,
             ..Default::default()
@@ -407,5 +1087,350 @@ mod integration_test {
         assert_eq!(events[0].as_log()["topic"], topic.into());
         assert!(events[0].as_log().contains("partition"));
         assert!(events[0].as_log().contains("offset"));
+        assert_eq!(
+            events[0].as_log()[Lookup::from("headers.foo")],
+            "bar".into()
+        );
+    }
+}
+
+/// Tests that exercise `kafka_source` against an in-process mock Kafka
+/// cluster (`rd_kafka_mock_cluster`) instead of a live broker, so they run
+/// as regular unit tests without Docker or external services.
+#[cfg(test)]
+mod mock_test {
+    use super::*;
+    use crate::{
+        event::Lookup,
+        test_util::{collect_n, trace_init},
+    };
+    use rdkafka::{
+        consumer::{BaseConsumer, DefaultConsumerContext},
+        message::OwnedHeaders,
+        mocking::MockCluster,
+        producer::{DefaultProducerContext, FutureProducer, FutureRecord},
+        util::Timeout,
+    };
+    use std::time::Duration;
+
+    /// A thin wrapper around an in-process mock broker, used so tests can
+    /// point `KafkaSourceConfig` at it the same way they would a real
+    /// cluster's `bootstrap_servers`.
+    struct MockBroker {
+        cluster: MockCluster<'static, DefaultProducerContext>,
+    }
+
+    impl MockBroker {
+        fn new() -> Self {
+            let cluster = MockCluster::new(1).expect("failed to start mock Kafka cluster");
+            Self { cluster }
+        }
+
+        fn bootstrap_servers(&self) -> String {
+            self.cluster.bootstrap_servers()
+        }
+
+        async fn produce(&self, topic: &str, key: &str, payload: &str, headers: OwnedHeaders) {
+            let producer: FutureProducer = ClientConfig::new()
+                .set("bootstrap.servers", &self.bootstrap_servers())
+                .create()
+                .expect("failed to create mock producer");
+
+            let record = FutureRecord::to(topic)
+                .payload(payload)
+                .key(key)
+                .headers(headers);
+
+            producer
+                .send(record, Timeout::Never)
+                .await
+                .expect("failed to produce to mock cluster");
+        }
+
+        /// Produces a message with no payload at all (as opposed to an empty
+        /// one), matching the "message had an empty payload" condition
+        /// `handle_invalid_message` forwards to the dead-letter queue.
+        async fn produce_empty_payload(&self, topic: &str, key: &str) {
+            let producer: FutureProducer = ClientConfig::new()
+                .set("bootstrap.servers", &self.bootstrap_servers())
+                .create()
+                .expect("failed to create mock producer");
+
+            let record: FutureRecord<str, [u8]> = FutureRecord::to(topic).key(key);
+
+            producer
+                .send(record, Timeout::Never)
+                .await
+                .expect("failed to produce to mock cluster");
+        }
+    }
+
+    fn mock_source_config(broker: &MockBroker, topic: &str, group_id: &str) -> KafkaSourceConfig {
+        KafkaSourceConfig {
+            bootstrap_servers: broker.bootstrap_servers(),
+            topics: vec![topic.to_string()],
+            group_id: group_id.to_string(),
+            auto_offset_reset: "beginning".into(),
+            session_timeout_ms: 6000,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn consumes_headers_timestamp_and_offset_from_mock_cluster() {
+        trace_init();
+        let broker = MockBroker::new();
+        let topic = "mock-test-topic";
+
+        broker
+            .produce(
+                topic,
+                "my key",
+                "my message",
+                OwnedHeaders::new().add("foo", "bar"),
+            )
+            .await;
+
+        let config = mock_source_config(&broker, topic, "mock-test-group");
+        let (tx, rx) = Pipeline::new_test();
+        tokio::spawn(kafka_source(&config, ShutdownSignal::noop(), tx).unwrap());
+        let events = collect_n(rx, 1).await;
+
+        assert_eq!(
+            events[0].as_log()[log_schema().message_key()],
+            "my message".into()
+        );
+        assert_eq!(
+            events[0].as_log()[Lookup::from("message_key")],
+            "my key".into()
+        );
+        assert_eq!(
+            events[0].as_log()[Lookup::from("headers.foo")],
+            "bar".into()
+        );
+        assert!(events[0].as_log().contains(log_schema().timestamp_key()));
+        assert!(events[0].as_log().contains("partition"));
+        assert!(events[0].as_log().contains("offset"));
+    }
+
+    #[tokio::test]
+    async fn starting_offsets_seek_skips_to_the_configured_offset() {
+        trace_init();
+        let broker = MockBroker::new();
+        let topic = "mock-test-starting-offsets-topic";
+
+        for message in &["first message", "second message", "third message"] {
+            broker
+                .produce(topic, "my key", message, OwnedHeaders::new())
+                .await;
+        }
+
+        // Skip the first message by seeking to offset 1 on startup.
+        let mut config = mock_source_config(&broker, topic, "mock-test-starting-offsets-group");
+        config.starting_offsets = Some(StartingOffsets::Explicit(
+            vec![(format!("{}:0", topic), 1)].into_iter().collect(),
+        ));
+
+        let (tx, rx) = Pipeline::new_test();
+        tokio::spawn(kafka_source(&config, ShutdownSignal::noop(), tx).unwrap());
+        let events = collect_n(rx, 2).await;
+
+        assert_eq!(
+            events[0].as_log()[log_schema().message_key()],
+            "second message".into()
+        );
+        assert_eq!(
+            events[1].as_log()[log_schema().message_key()],
+            "third message".into()
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_on_revoke_commits_the_stored_offset_before_a_rebalance() {
+        trace_init();
+        let broker = MockBroker::new();
+        let topic = "mock-test-rebalance-topic";
+        let group_id = "mock-test-rebalance-group";
+
+        broker
+            .produce(topic, "my key", "first message", OwnedHeaders::new())
+            .await;
+
+        let mut config = mock_source_config(&broker, topic, group_id);
+        config.commit_mode = KafkaCommitMode::SyncOnRevoke;
+
+        let (tx, rx) = Pipeline::new_test();
+        tokio::spawn(kafka_source(&config, ShutdownSignal::noop(), tx).unwrap());
+        collect_n(rx, 1).await;
+
+        // Give the source's consumer a moment to store the offset for the
+        // message it just processed, then join a second member into the
+        // same group. With a single partition this forces a rebalance that
+        // revokes it from the source's consumer; if `pre_rebalance` didn't
+        // actually commit the stored offset (the bug this test guards
+        // against), the committed offset below would still be unset.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let rival: BaseConsumer<DefaultConsumerContext> = ClientConfig::new()
+            .set("group.id", group_id)
+            .set("bootstrap.servers", &broker.bootstrap_servers())
+            .set("session.timeout.ms", "6000")
+            .create()
+            .expect("failed to create rival consumer");
+        rival
+            .subscribe(&[topic])
+            .expect("failed to subscribe rival consumer");
+
+        let mut joined = false;
+        for _ in 0..20 {
+            if rival.poll(Timeout::After(Duration::from_millis(250))).is_some() {
+                joined = true;
+                break;
+            }
+        }
+        assert!(joined, "rival consumer never joined the consumer group");
+
+        let committed = rival
+            .committed(Timeout::After(Duration::from_secs(5)))
+            .expect("failed to fetch committed offsets");
+        let offset = committed
+            .find_partition(topic, 0)
+            .expect("missing partition in committed offsets")
+            .offset();
+        assert_eq!(offset, Offset::Offset(1));
+    }
+
+    #[tokio::test]
+    async fn acknowledgements_store_the_offset_once_the_sink_delivers() {
+        trace_init();
+        let broker = MockBroker::new();
+        let topic = "mock-test-acknowledgements-topic";
+        let group_id = "mock-test-acknowledgements-group";
+
+        for message in &["first message", "second message", "third message"] {
+            broker
+                .produce(topic, "my key", message, OwnedHeaders::new())
+                .await;
+        }
+
+        let mut config = mock_source_config(&broker, topic, group_id);
+        config.acknowledgements = true;
+        config.commit_interval_ms = 50;
+
+        let (tx, rx) = Pipeline::new_test();
+        tokio::spawn(kafka_source(&config, ShutdownSignal::noop(), tx).unwrap());
+        let events = collect_n(rx, 3).await;
+        assert_eq!(events.len(), 3);
+
+        // `Pipeline::new_test` acknowledges every event as delivered as soon
+        // as it's received, so each message's per-partition finalizer queue
+        // should have caught up and stored its offset in order (including
+        // any that happened to resolve out of order) well within one
+        // auto-commit interval.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let rival: BaseConsumer<DefaultConsumerContext> = ClientConfig::new()
+            .set("group.id", group_id)
+            .set("bootstrap.servers", &broker.bootstrap_servers())
+            .set("session.timeout.ms", "6000")
+            .create()
+            .expect("failed to create rival consumer");
+        rival
+            .subscribe(&[topic])
+            .expect("failed to subscribe rival consumer");
+
+        let mut joined = false;
+        for _ in 0..20 {
+            if rival.poll(Timeout::After(Duration::from_millis(250))).is_some() {
+                joined = true;
+                break;
+            }
+        }
+        assert!(joined, "rival consumer never joined the consumer group");
+
+        let committed = rival
+            .committed(Timeout::After(Duration::from_secs(5)))
+            .expect("failed to fetch committed offsets");
+        let offset = committed
+            .find_partition(topic, 0)
+            .expect("missing partition in committed offsets")
+            .offset();
+        assert_eq!(offset, Offset::Offset(3));
+    }
+
+    #[tokio::test]
+    async fn invalid_message_offset_is_stored_only_after_the_dlq_write_succeeds() {
+        trace_init();
+        let broker = MockBroker::new();
+        let topic = "mock-test-dlq-topic";
+        let dlq_topic = "mock-test-dlq-topic-dlq";
+        let group_id = "mock-test-dlq-group";
+
+        broker.produce_empty_payload(topic, "bad key").await;
+
+        let mut config = mock_source_config(&broker, topic, group_id);
+        config.commit_interval_ms = 50;
+        config.dlq = Some(KafkaDlqConfig {
+            topic: dlq_topic.to_string(),
+            window_size: 10,
+            max_invalid_ratio: 1.0,
+            max_consecutive_failures: 1000,
+        });
+
+        let (tx, _rx) = Pipeline::new_test();
+        tokio::spawn(kafka_source(&config, ShutdownSignal::noop(), tx).unwrap());
+
+        // Nothing is ever forwarded downstream for a DLQ'd message, so read
+        // the dead-letter record back from the mock cluster directly.
+        let dlq_consumer: StreamConsumer<DefaultConsumerContext> = ClientConfig::new()
+            .set("group.id", "mock-test-dlq-reader")
+            .set("bootstrap.servers", &broker.bootstrap_servers())
+            .set("auto.offset.reset", "beginning")
+            .create()
+            .expect("failed to create DLQ reader");
+        dlq_consumer
+            .subscribe(&[dlq_topic])
+            .expect("failed to subscribe to DLQ topic");
+
+        let dlq_message = tokio::time::timeout(Duration::from_secs(10), dlq_consumer.recv())
+            .await
+            .expect("timed out waiting for a dead-letter record")
+            .expect("failed to read dead-letter record");
+        let record: serde_json::Value = serde_json::from_slice(
+            dlq_message
+                .payload()
+                .expect("dead-letter record had no payload"),
+        )
+        .expect("dead-letter record was not valid JSON");
+        assert_eq!(record["topic"], topic);
+        assert_eq!(record["partition"], 0);
+        assert_eq!(record["offset"], 0);
+        assert_eq!(record["reason"], "message had an empty payload");
+
+        // The source's offset is only stored once the write above has
+        // succeeded; give the auto-commit interval time to flush it, then
+        // confirm a fresh consumer in the same group resumes past the
+        // invalid message instead of redelivering it.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let rival: BaseConsumer<DefaultConsumerContext> = ClientConfig::new()
+            .set("group.id", group_id)
+            .set("bootstrap.servers", &broker.bootstrap_servers())
+            .set("session.timeout.ms", "6000")
+            .create()
+            .expect("failed to create rival consumer");
+        rival
+            .subscribe(&[topic])
+            .expect("failed to subscribe rival consumer");
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition(topic, 0);
+        let committed = rival
+            .committed_offsets(partitions, Timeout::After(Duration::from_secs(5)))
+            .expect("failed to fetch committed offsets");
+        let offset = committed
+            .find_partition(topic, 0)
+            .expect("missing partition in committed offsets")
+            .offset();
+        assert_eq!(offset, Offset::Offset(1));
     }
 }